@@ -0,0 +1,175 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+/// Hashes media bytes into the cache key used by `MediaCache` implementations.
+pub fn cache_key_for(bytes: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(bytes);
+    format!("{:x}", hasher.finalize())
+}
+
+/// A cache for downloaded media blobs, keyed by the SHA-256 hash of their
+/// bytes. Lets repeated reposts/quote posts reuse an already-downloaded blob
+/// instead of fetching it from the network again. Only the downloaded bytes
+/// are stored; the handle returned by an earlier upload is not kept.
+#[async_trait]
+pub trait MediaCache: Send + Sync {
+    /// Returns the cached bytes for `cache_key`, or `None` if not cached yet.
+    async fn get(&self, cache_key: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Stores `bytes` under `cache_key`, overwriting any previous entry.
+    async fn put(&self, cache_key: &str, bytes: &[u8]) -> Result<()>;
+}
+
+/// Stores cached media blobs as files on local disk, named after their
+/// content hash.
+pub struct FilesystemMediaCache {
+    base_dir: PathBuf,
+}
+
+impl FilesystemMediaCache {
+    pub fn new(base_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            base_dir: base_dir.into(),
+        }
+    }
+
+    fn path_for(&self, cache_key: &str) -> PathBuf {
+        self.base_dir.join(cache_key)
+    }
+}
+
+#[async_trait]
+impl MediaCache for FilesystemMediaCache {
+    async fn get(&self, cache_key: &str) -> Result<Option<Vec<u8>>> {
+        match tokio::fs::read(self.path_for(cache_key)).await {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn put(&self, cache_key: &str, bytes: &[u8]) -> Result<()> {
+        tokio::fs::create_dir_all(&self.base_dir).await?;
+        tokio::fs::write(self.path_for(cache_key), bytes).await?;
+        Ok(())
+    }
+}
+
+/// Stores cached media blobs in an S3-compatible bucket (AWS S3, R2, Minio,
+/// ...), so operators who don't want local disk state can plug in object
+/// storage instead.
+pub struct S3MediaCache {
+    client: aws_sdk_s3::Client,
+    bucket: String,
+}
+
+impl S3MediaCache {
+    pub fn new(client: aws_sdk_s3::Client, bucket: impl Into<String>) -> Self {
+        Self {
+            client,
+            bucket: bucket.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl MediaCache for S3MediaCache {
+    async fn get(&self, cache_key: &str) -> Result<Option<Vec<u8>>> {
+        let result = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(cache_key)
+            .send()
+            .await;
+        match result {
+            Ok(output) => {
+                let bytes = output.body.collect().await?.into_bytes();
+                Ok(Some(bytes.to_vec()))
+            }
+            Err(err) if err.as_service_error().is_some_and(|e| e.is_no_such_key()) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn put(&self, cache_key: &str, bytes: &[u8]) -> Result<()> {
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(cache_key)
+            .body(bytes.to_vec().into())
+            .send()
+            .await?;
+        Ok(())
+    }
+}
+
+/// Maps an attachment URL to the content hash it was last downloaded and
+/// cached under, so a later run knows which `cache_key` to pass into
+/// `download_with_cache` instead of always fetching from the network.
+pub type UrlCacheIndex = HashMap<String, String>;
+
+/// Reads the JSON encoded URL cache index file from disk, or provides an
+/// empty default index if it doesn't exist yet or fails to parse.
+pub fn read_url_index(index_file: &str) -> UrlCacheIndex {
+    match fs::read_to_string(index_file) {
+        Ok(json) => serde_json::from_str(&json).unwrap_or_default(),
+        Err(_) => UrlCacheIndex::new(),
+    }
+}
+
+/// Writes the URL cache index to disk as JSON, so `cache_key`s looked up
+/// here keep working across process restarts.
+pub fn write_url_index(index_file: &str, index: &UrlCacheIndex) -> Result<()> {
+    let json = serde_json::to_string(index)?;
+    fs::write(index_file, json)?;
+    Ok(())
+}
+
+/// Downloads `url`, consulting `cache` first so media that was already
+/// fetched (and uploaded) in an earlier sync run isn't fetched again.
+pub async fn download_with_cache(
+    cache: &dyn MediaCache,
+    url: &str,
+    known_cache_key: Option<&str>,
+) -> Result<(Vec<u8>, String)> {
+    if let Some(cache_key) = known_cache_key {
+        if let Some(bytes) = cache.get(cache_key).await? {
+            return Ok((bytes, cache_key.to_string()));
+        }
+    }
+
+    let bytes = reqwest::get(url).await?.bytes().await?.to_vec();
+    let cache_key = cache_key_for(&bytes);
+    cache.put(&cache_key, &bytes).await?;
+    Ok((bytes, cache_key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test that bytes put into a FilesystemMediaCache can be read back under
+    // the same cache key.
+    #[tokio::test]
+    async fn filesystem_media_cache_round_trip() {
+        let base_dir = std::env::temp_dir().join(format!(
+            "mastodon_bluesky_sync_media_cache_test_{}",
+            std::process::id()
+        ));
+        let cache = FilesystemMediaCache::new(&base_dir);
+        let bytes = b"test media bytes".to_vec();
+        let cache_key = cache_key_for(&bytes);
+
+        assert!(cache.get(&cache_key).await.unwrap().is_none());
+        cache.put(&cache_key, &bytes).await.unwrap();
+        assert_eq!(cache.get(&cache_key).await.unwrap(), Some(bytes));
+
+        fs::remove_dir_all(&base_dir).ok();
+    }
+}
@@ -0,0 +1,230 @@
+use crate::sync::{filter_posted_before, ingest_status, IncomingStatus, StatusUpdates};
+use crate::SyncOptions;
+use anyhow::Result;
+use async_trait::async_trait;
+use bsky_sdk::api::app::bsky::feed::defs::FeedViewPostData;
+use bsky_sdk::api::types::Object;
+use megalodon::entities::Status;
+use std::collections::{HashSet, VecDeque};
+use std::time::Duration;
+use tokio::time::sleep;
+
+// How many recently seen statuses to keep per platform for loop prevention
+// in ingest_status(). Replies are skipped there, so no thread lookups use it.
+const RECENT_HISTORY_CAPACITY: usize = 200;
+
+// A bounded, oldest-evicted window of recently seen statuses for one
+// platform, fed to ingest_status() instead of re-pulling a whole timeline.
+struct RecentHistory<T> {
+    statuses: VecDeque<T>,
+    capacity: usize,
+}
+
+impl<T> RecentHistory<T> {
+    fn new(capacity: usize) -> Self {
+        RecentHistory {
+            statuses: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    fn push(&mut self, status: T) {
+        if self.statuses.len() == self.capacity {
+            self.statuses.pop_front();
+        }
+        self.statuses.push_back(status);
+    }
+
+    fn as_slice(&self) -> Vec<T>
+    where
+        T: Clone,
+    {
+        self.statuses.iter().cloned().collect()
+    }
+}
+
+/// A real-time source of new statuses: Mastodon's user streaming WebSocket
+/// or Bluesky's firehose/Jetstream. Implementations reconnect internally and
+/// use `reconnect_with_backfill` to report what was missed while
+/// disconnected.
+#[async_trait]
+pub trait StatusSource: Send {
+    /// Blocks until the next status is available from the stream.
+    async fn next_event(&mut self) -> Result<IncomingStatus>;
+
+    /// Called after `next_event` fails. Re-establishes the connection and
+    /// returns any statuses posted while disconnected, oldest first, so that
+    /// the sync loop doesn't miss anything that happened during the outage.
+    async fn reconnect_with_backfill(&mut self) -> Result<Vec<IncomingStatus>>;
+}
+
+/// Runs the streaming sync loop: consumes events from `source` one at a
+/// time, feeds them into ingest_status() together with a bounded window of
+/// recently seen statuses, and invokes `on_updates` with the new posts to
+/// make for each event as it arrives.
+///
+/// This only returns with an error: when `source` fails to reconnect, or when
+/// filtering or `on_updates` fails for an event. Otherwise it runs for the
+/// life of the process; callers run it as a
+/// background task and act on each `on_updates` call rather than waiting for
+/// a final result.
+///
+/// This gives near-real-time crossposting with much lower API usage than
+/// polling determine_posts() on a timer, while reusing the exact same,
+/// already-tested diff logic.
+pub async fn run_streaming_sync(
+    mut source: Box<dyn StatusSource>,
+    options: &SyncOptions,
+    post_cache: &mut HashSet<String>,
+    mut on_updates: impl FnMut(StatusUpdates) -> Result<()>,
+) -> Result<()> {
+    let mut recent_mastodon: RecentHistory<Status> = RecentHistory::new(RECENT_HISTORY_CAPACITY);
+    let mut recent_bsky: RecentHistory<Object<FeedViewPostData>> =
+        RecentHistory::new(RECENT_HISTORY_CAPACITY);
+
+    loop {
+        let event = match source.next_event().await {
+            Ok(event) => event,
+            Err(err) => {
+                eprintln!("Error: streaming connection dropped: {err}, reconnecting");
+                // Give the server a moment before reconnecting.
+                sleep(Duration::from_secs(5)).await;
+                match source.reconnect_with_backfill().await {
+                    Ok(backfilled) => {
+                        for event in backfilled {
+                            ingest_event(
+                                event,
+                                &mut recent_mastodon,
+                                &mut recent_bsky,
+                                options,
+                                post_cache,
+                                &mut on_updates,
+                            )?;
+                        }
+                        continue;
+                    }
+                    Err(err) => {
+                        eprintln!("Error: failed to reconnect to streaming source: {err}");
+                        return Err(err);
+                    }
+                }
+            }
+        };
+        ingest_event(
+            event,
+            &mut recent_mastodon,
+            &mut recent_bsky,
+            options,
+            post_cache,
+            &mut on_updates,
+        )?;
+    }
+}
+
+fn ingest_event(
+    event: IncomingStatus,
+    recent_mastodon: &mut RecentHistory<Status>,
+    recent_bsky: &mut RecentHistory<Object<FeedViewPostData>>,
+    options: &SyncOptions,
+    post_cache: &mut HashSet<String>,
+    on_updates: &mut impl FnMut(StatusUpdates) -> Result<()>,
+) -> Result<()> {
+    let new_updates = ingest_status(
+        &event,
+        &recent_mastodon.as_slice(),
+        &recent_bsky.as_slice(),
+        options,
+    );
+    let new_updates = filter_posted_before(new_updates, post_cache)?;
+    for toot in &new_updates.toots {
+        post_cache.insert(toot.text.clone());
+    }
+    for post in &new_updates.bsky_posts {
+        post_cache.insert(post.text.clone());
+    }
+
+    match event {
+        IncomingStatus::Mastodon(toot) => recent_mastodon.push(toot),
+        IncomingStatus::Bluesky(post) => recent_bsky.push(post),
+    }
+
+    on_updates(new_updates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::fs;
+
+    fn read_toot_event(file_name: &str) -> IncomingStatus {
+        let json = fs::read_to_string(file_name).unwrap();
+        let toot: Status = serde_json::from_str(&json).unwrap();
+        IncomingStatus::Mastodon(toot)
+    }
+
+    // Test that RecentHistory evicts the oldest entry once it's full instead
+    // of growing without bound.
+    #[test]
+    fn recent_history_evicts_oldest_at_capacity() {
+        let mut history: RecentHistory<i32> = RecentHistory::new(2);
+        history.push(1);
+        history.push(2);
+        history.push(3);
+        assert_eq!(history.as_slice(), vec![2, 3]);
+    }
+
+    // A StatusSource that replays a fixed script of events and a single
+    // backfill result, for testing run_streaming_sync() without a real
+    // connection.
+    struct FakeStatusSource {
+        events: VecDeque<Result<IncomingStatus>>,
+        backfill_result: Option<Result<Vec<IncomingStatus>>>,
+    }
+
+    #[async_trait]
+    impl StatusSource for FakeStatusSource {
+        async fn next_event(&mut self) -> Result<IncomingStatus> {
+            self.events
+                .pop_front()
+                .unwrap_or_else(|| Err(anyhow::anyhow!("no more events")))
+        }
+
+        async fn reconnect_with_backfill(&mut self) -> Result<Vec<IncomingStatus>> {
+            self.backfill_result
+                .take()
+                .unwrap_or_else(|| Err(anyhow::anyhow!("no backfill configured")))
+        }
+    }
+
+    // Test that a dropped connection triggers reconnect_with_backfill() and
+    // that the backfilled event is delivered via on_updates() just like a
+    // live event would be.
+    #[tokio::test(start_paused = true)]
+    async fn run_streaming_sync_delivers_backfill_after_reconnect() {
+        let backfilled_event = read_toot_event("tests/mastodon_private_toot.json");
+        let source = FakeStatusSource {
+            events: VecDeque::from(vec![
+                Err(anyhow::anyhow!("connection dropped")),
+                Err(anyhow::anyhow!("connection dropped again")),
+            ]),
+            backfill_result: Some(Ok(vec![backfilled_event])),
+        };
+
+        let delivered = RefCell::new(Vec::new());
+        let options = SyncOptions::default();
+        let mut post_cache = HashSet::new();
+
+        let result = run_streaming_sync(Box::new(source), &options, &mut post_cache, |updates| {
+            delivered.borrow_mut().push(updates);
+            Ok(())
+        })
+        .await;
+
+        // The second reconnect attempt fails, which is how the fake source
+        // signals "stop", so the loop should surface that error.
+        assert!(result.is_err());
+        assert_eq!(delivered.borrow().len(), 1);
+        assert_eq!(delivered.borrow()[0].bsky_posts.len(), 1);
+    }
+}
@@ -1,11 +1,14 @@
 use anyhow::Result;
 use bsky_sdk::api::app::bsky::embed::record::ViewRecordRefs;
-use bsky_sdk::api::app::bsky::feed::defs::{FeedViewPostData, PostViewData, PostViewEmbedRefs};
+use bsky_sdk::api::app::bsky::feed::defs::{
+    FeedViewPostData, PostViewData, PostViewEmbedRefs, ReplyRefParentRefs,
+};
 use bsky_sdk::api::app::bsky::richtext::facet::MainFeaturesItem;
 use bsky_sdk::api::types::{Object, TryFromUnknown, Union};
+use megalodon::entities::status::StatusVisibility;
 use megalodon::entities::Status;
 use regex::Regex;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use unicode_segmentation::UnicodeSegmentation;
 
@@ -43,14 +46,62 @@ pub struct NewStatus {
 pub struct NewMedia {
     pub attachment_url: String,
     pub alt_text: Option<String>,
+    // SHA-256 hash of the attachment bytes, once known. Lets the download
+    // path in media_cache consult a MediaCache instead of re-fetching media
+    // that was already downloaded in an earlier run. Upload handles are not
+    // cached, so this does not skip re-uploading that media.
+    pub cache_key: Option<String>,
 }
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone)]
 pub struct SyncOptions {
     pub sync_reblogs: bool,
     pub sync_reposts: bool,
     pub sync_hashtag_bluesky: Option<String>,
     pub sync_hashtag_mastodon: Option<String>,
+    // Posts whose decoded text matches any of these patterns are dropped
+    // entirely, e.g. to suppress NSFW tags or link-only posts.
+    pub content_filters: Vec<Regex>,
+    // Blocked keywords/phrases, checked exactly like content_filters. The
+    // patterns are used as given, so word boundaries and case-insensitivity
+    // must be part of each pattern, e.g. `(?i)\bspam\b`.
+    pub keyword_blocklist: Vec<Regex>,
+    // Only sync Mastodon toots with `public` visibility to Bluesky, skipping
+    // unlisted, private and direct toots. Defaults to off to preserve
+    // current behavior. Bluesky has no equivalent visibility model, so this
+    // only gates the Mastodon -> Bluesky direction.
+    pub sync_only_public: bool,
+    // Maximum character length of a Bluesky post before it gets shortened.
+    // Defaults to Bluesky's own limit of 300 characters, but some
+    // Bluesky-compatible PDS deployments differ.
+    pub bsky_max_chars: usize,
+    // Maximum character length of a Mastodon toot before it gets shortened.
+    // Defaults to 500 characters, but custom instances can raise their own
+    // limit much higher.
+    pub mastodon_max_chars: usize,
+    // Maps an attachment URL to the content hash it was cached under in an
+    // earlier run, read via media_cache::read_url_index(). Lets
+    // bsky_get_attachments()/toot_get_attachments() populate
+    // NewMedia.cache_key so download_with_cache() can actually hit the
+    // cache instead of always re-fetching from the network.
+    pub media_cache_url_index: crate::media_cache::UrlCacheIndex,
+}
+
+impl Default for SyncOptions {
+    fn default() -> Self {
+        SyncOptions {
+            sync_reblogs: false,
+            sync_reposts: false,
+            sync_hashtag_bluesky: None,
+            sync_hashtag_mastodon: None,
+            content_filters: Vec::new(),
+            keyword_blocklist: Vec::new(),
+            sync_only_public: false,
+            bsky_max_chars: 300,
+            mastodon_max_chars: 500,
+            media_cache_url_index: crate::media_cache::UrlCacheIndex::new(),
+        }
+    }
 }
 
 /// This is the main synchronization function that can be tested without
@@ -73,118 +124,455 @@ pub fn determine_posts(
         bsky_posts: Vec::new(),
         toots: Vec::new(),
     };
-    'bsky: for post in bsky_statuses {
-        // Skip replies, they are handled in determine_thread_replies().
-        if let Some(_reply) = &post.reply {
-            continue;
+    // Maps a root status' id (Bluesky AT-URI or Mastodon toot id) to the
+    // index of its cross-posted NewStatus in `updates`, so that
+    // determine_thread_replies() can attach replies to the right thread.
+    let mut bsky_root_indices: HashMap<String, usize> = HashMap::new();
+    let mut toot_root_indices: HashMap<String, usize> = HashMap::new();
+    // Tracks, for each entry pushed into `updates.toots`/`updates.bsky_posts`,
+    // the index of the source status it came from in `bsky_statuses`/
+    // `mastodon_statuses` (which are newest-first). determine_thread_replies()
+    // appends standalone continuation posts after this loop has already run,
+    // so without this we'd lose track of where those posts actually belong
+    // chronologically relative to the roots found above.
+    let mut toots_order: Vec<usize> = Vec::new();
+    let mut bsky_posts_order: Vec<usize> = Vec::new();
+    for (index, post) in bsky_statuses.iter().enumerate() {
+        match diff_bsky_post(post, mastodon_statuses, options) {
+            // The post already exists on Mastodon. Bluesky timelines are
+            // newest-first, so everything older is already synced too.
+            DiffOutcome::AlreadySynced => break,
+            DiffOutcome::Skipped => continue,
+            DiffOutcome::New(new_status) => {
+                updates.toots.push(new_status);
+                toots_order.push(index);
+                bsky_root_indices.insert(post.post.uri.clone(), updates.toots.len() - 1);
+            }
         }
+    }
 
-        if !options.sync_reposts {
-            if let Some(_reskeet) = &post.post.viewer {
-                if let Some(_repost) = &_reskeet.repost {
-                    // Skip reskeets when sync_reposts is disabled
-                    continue;
-                }
+    for (index, toot) in mastodon_statuses.iter().enumerate() {
+        match diff_toot(toot, bsky_statuses, options) {
+            // The toot already exists on Bluesky. Mastodon timelines are
+            // newest-first, so everything older is already synced too.
+            DiffOutcome::AlreadySynced => break,
+            DiffOutcome::Skipped => continue,
+            DiffOutcome::New(new_status) => {
+                updates.bsky_posts.push(new_status);
+                bsky_posts_order.push(index);
+                toot_root_indices.insert(toot.id.clone(), updates.bsky_posts.len() - 1);
             }
         }
+    }
 
-        for toot in mastodon_statuses {
-            // Skip replies because we don't want to sync them here.
-            if let Some(_id) = &toot.in_reply_to_id {
-                continue;
+    determine_thread_replies(
+        mastodon_statuses,
+        bsky_statuses,
+        options,
+        &mut bsky_root_indices,
+        &mut toot_root_indices,
+        &mut updates,
+        &mut toots_order,
+        &mut bsky_posts_order,
+    );
+
+    // Sort by the source status' original (newest-first) index so that
+    // standalone continuation posts appended by determine_thread_replies()
+    // interleave correctly with the roots found above, instead of trailing
+    // behind them regardless of their real chronological position.
+    sort_by_order(&mut updates.toots, &toots_order);
+    sort_by_order(&mut updates.bsky_posts, &bsky_posts_order);
+
+    // Older posts should come first to preserve the ordering of posts to
+    // synchronize.
+    updates.reverse_order();
+    updates
+}
+
+// Reorders `items` so it matches ascending `order`, where `order[i]` is the
+// originating index (newest-first) of `items[i]`.
+fn sort_by_order(items: &mut Vec<NewStatus>, order: &[usize]) {
+    let mut paired: Vec<(usize, NewStatus)> =
+        order.iter().copied().zip(items.drain(..)).collect();
+    paired.sort_by_key(|(index, _)| *index);
+    items.extend(paired.into_iter().map(|(_, status)| status));
+}
+
+// The result of diffing a single status against the other platform's
+// statuses. Shared between determine_posts() (diffing a whole timeline) and
+// ingest_status() (diffing a single status from a streaming event source).
+enum DiffOutcome {
+    // The status is already synced to the other platform.
+    AlreadySynced,
+    // The status should not be synced (reply, reblog, filtered, ...).
+    Skipped,
+    // The status is new and should be posted as given.
+    New(NewStatus),
+}
+
+// Diffs a single Bluesky post against Mastodon statuses, deciding whether it
+// should be cross-posted as a new toot.
+fn diff_bsky_post(
+    post: &Object<FeedViewPostData>,
+    mastodon_statuses: &[Status],
+    options: &SyncOptions,
+) -> DiffOutcome {
+    // Skip replies, they are handled in determine_thread_replies().
+    if post.reply.is_some() {
+        return DiffOutcome::Skipped;
+    }
+
+    if !options.sync_reposts {
+        if let Some(viewer) = &post.post.viewer {
+            if viewer.repost.is_some() {
+                // Skip reskeets when sync_reposts is disabled
+                return DiffOutcome::Skipped;
+            }
+        }
+    }
+
+    for toot in mastodon_statuses {
+        // Skip replies because we don't want to sync them here.
+        if toot.in_reply_to_id.is_some() {
+            continue;
+        }
+        // If the post already exists we can stop here and know that we are
+        // synced.
+        if toot_and_post_are_equal(toot, post, options) {
+            return DiffOutcome::AlreadySynced;
+        }
+    }
+
+    // The post is not on Mastodon yet, check if we should post it.
+    // Fetch the post text into a String object
+    let decoded_post = match bsky_post_unshorten_decode(post, options) {
+        Ok(text) => text,
+        Err(err) => {
+            eprintln!("Error: failed to decode Bluesky post, skipping: {err}");
+            return DiffOutcome::Skipped;
+        }
+    };
+
+    // Check if hashtag filtering is enabled and if the post matches.
+    if let Some(sync_hashtag) = &options.sync_hashtag_bluesky {
+        if !sync_hashtag.is_empty() && !decoded_post.contains(sync_hashtag) {
+            // Skip if a sync hashtag is set and the string doesn't match.
+            return DiffOutcome::Skipped;
+        }
+    }
+
+    // Drop the post entirely if it matches a content filter or a
+    // blocklisted keyword.
+    if matches_any(&options.content_filters, &decoded_post)
+        || matches_any(&options.keyword_blocklist, &decoded_post)
+    {
+        return DiffOutcome::Skipped;
+    }
+
+    DiffOutcome::New(NewStatus {
+        text: decoded_post,
+        attachments: bsky_get_attachments(post, options),
+        replies: Vec::new(),
+        in_reply_to_id: None,
+    })
+}
+
+// Diffs a single Mastodon toot against Bluesky posts, deciding whether it
+// should be cross-posted as a new Bluesky post.
+fn diff_toot(
+    toot: &Status,
+    bsky_statuses: &[Object<FeedViewPostData>],
+    options: &SyncOptions,
+) -> DiffOutcome {
+    // Skip replies, they are handled in determine_thread_replies().
+    if toot.in_reply_to_id.is_some() {
+        return DiffOutcome::Skipped;
+    }
+
+    if toot.reblog.is_some() && !options.sync_reblogs {
+        // Skip reblogs when sync_reblogs is disabled
+        return DiffOutcome::Skipped;
+    }
+    if options.sync_only_public && !toot_is_public(toot) {
+        // Skip unlisted/private/direct toots when sync_only_public is enabled.
+        return DiffOutcome::Skipped;
+    }
+    let fulltext = mastodon_toot_get_text(toot);
+    // If this is a reblog/boost then take the URL to the original toot.
+    let post = match &toot.reblog {
+        None => bsky_post_shorten(&fulltext, &toot.url, options.bsky_max_chars),
+        Some(reblog) => bsky_post_shorten(&fulltext, &reblog.url, options.bsky_max_chars),
+    };
+    // Skip direct toots to other Mastodon users, even if they are public.
+    if post.starts_with('@') {
+        return DiffOutcome::Skipped;
+    }
+
+    for bsky_post in bsky_statuses {
+        // If the toot already exists we can stop here and know that we are
+        // synced.
+        if toot_and_post_are_equal(toot, bsky_post, options) {
+            return DiffOutcome::AlreadySynced;
+        }
+    }
+
+    // The toot is not on Bluesky yet, check if we should post it.
+    // Check if hashtag filtering is enabled and if the post matches.
+    if let Some(sync_hashtag) = &options.sync_hashtag_mastodon {
+        if !sync_hashtag.is_empty() && !fulltext.contains(sync_hashtag) {
+            // Skip if a sync hashtag is set and the string doesn't match.
+            return DiffOutcome::Skipped;
+        }
+    }
+
+    // Drop the toot entirely if it matches a content filter or a
+    // blocklisted keyword.
+    if matches_any(&options.content_filters, &fulltext)
+        || matches_any(&options.keyword_blocklist, &fulltext)
+    {
+        return DiffOutcome::Skipped;
+    }
+
+    DiffOutcome::New(NewStatus {
+        text: post,
+        attachments: toot_get_attachments(toot, options),
+        replies: Vec::new(),
+        in_reply_to_id: None,
+    })
+}
+
+// A single new status received from a streaming event source: Mastodon's
+// user streaming WebSocket or Bluesky's firehose/Jetstream.
+pub enum IncomingStatus {
+    Mastodon(Status),
+    Bluesky(Object<FeedViewPostData>),
+}
+
+/// Feeds one new status from a streaming event source into the same diff
+/// logic that determine_posts() uses for full timelines, without re-pulling
+/// either platform's whole timeline.
+///
+/// `recent_mastodon`/`recent_bsky` should be a bounded window of recently
+/// seen statuses on the platform other than the one `status` came from; it
+/// is used both for loop prevention (via toot_and_post_are_equal()) and to
+/// notice that a status was already synced. As with determine_posts(),
+/// callers should still pass the result through filter_posted_before() to
+/// guard against double-posting across process restarts.
+pub fn ingest_status(
+    status: &IncomingStatus,
+    recent_mastodon: &[Status],
+    recent_bsky: &[Object<FeedViewPostData>],
+    options: &SyncOptions,
+) -> StatusUpdates {
+    let mut updates = StatusUpdates {
+        bsky_posts: Vec::new(),
+        toots: Vec::new(),
+    };
+    match status {
+        IncomingStatus::Mastodon(toot) => {
+            if let DiffOutcome::New(new_status) = diff_toot(toot, recent_bsky, options) {
+                updates.bsky_posts.push(new_status);
             }
-            // If the post already exists we can stop here and know that we are
-            // synced.
-            if toot_and_post_are_equal(toot, post) {
-                break 'bsky;
+        }
+        IncomingStatus::Bluesky(post) => {
+            if let DiffOutcome::New(new_status) = diff_bsky_post(post, recent_mastodon, options) {
+                updates.toots.push(new_status);
             }
         }
+    }
+    updates
+}
+
+// Reconstructs self-threads on both platforms and attaches replies to the
+// NewStatus of their root, so that threads are cross-posted in full instead
+// of just their first toot/post.
+//
+// `bsky_root_indices` and `toot_root_indices` map a root status' id to the
+// index of its cross-posted NewStatus in `updates`; entries are added here as
+// replies get resolved so that deeper reply chains keep attaching correctly.
+fn determine_thread_replies(
+    mastodon_statuses: &[Status],
+    bsky_statuses: &[Object<FeedViewPostData>],
+    options: &SyncOptions,
+    bsky_root_indices: &mut HashMap<String, usize>,
+    toot_root_indices: &mut HashMap<String, usize>,
+    updates: &mut StatusUpdates,
+    toots_order: &mut Vec<usize>,
+    bsky_posts_order: &mut Vec<usize>,
+) {
+    // Walk oldest to newest so that a reply's parent has already been
+    // resolved by the time the reply itself is processed.
+    for (index, post) in bsky_statuses.iter().enumerate().rev() {
+        let Some(reply) = &post.reply else {
+            continue;
+        };
+        let Union::Refs(ReplyRefParentRefs::PostView(parent)) = &reply.parent else {
+            // Parent is blocked or not found, we can't thread off of it.
+            continue;
+        };
+        // Only thread replies that are entirely self-authored, matching the
+        // existing skip of toots addressed to other accounts with '@'.
+        if parent.author.handle.as_str() != post.post.author.handle.as_str() {
+            continue;
+        }
 
-        // The post is not on Mastodon yet, check if we should post it.
-        // Fetch the post text into a String object
-        let decoded_post = bsky_post_unshorten_decode(post);
+        // Skip if this reply was already synced to Mastodon in a previous run.
+        if mastodon_statuses
+            .iter()
+            .any(|toot| toot.in_reply_to_id.is_some() && toot_and_post_are_equal(toot, post, options))
+        {
+            continue;
+        }
 
-        // Check if hashtag filtering is enabled and if the post matches.
+        let decoded_post = match bsky_post_unshorten_decode(post, options) {
+            Ok(text) => text,
+            Err(err) => {
+                eprintln!("Error: failed to decode Bluesky reply, skipping: {err}");
+                continue;
+            }
+        };
         if let Some(sync_hashtag) = &options.sync_hashtag_bluesky {
             if !sync_hashtag.is_empty() && !decoded_post.contains(sync_hashtag) {
-                // Skip if a sync hashtag is set and the string doesn't match.
                 continue;
             }
         }
+        if matches_any(&options.content_filters, &decoded_post)
+            || matches_any(&options.keyword_blocklist, &decoded_post)
+        {
+            continue;
+        }
 
-        updates.toots.push(NewStatus {
+        let new_reply = NewStatus {
             text: decoded_post,
-            attachments: bsky_get_attachments(post),
+            attachments: bsky_get_attachments(post, options),
             replies: Vec::new(),
             in_reply_to_id: None,
-        });
+        };
+
+        if let Some(&root_index) = bsky_root_indices.get(&parent.uri) {
+            // The parent was already cross-posted as part of this run, nest
+            // the reply under its replies so the thread keeps its order.
+            updates.toots[root_index].replies.push(new_reply);
+            bsky_root_indices.insert(post.post.uri.clone(), root_index);
+        } else if let Some(synced_id) = find_synced_toot_id(mastodon_statuses, parent, options) {
+            // The parent was posted in an earlier run, post this reply on
+            // its own but point it back at the already-synced toot.
+            updates.toots.push(NewStatus {
+                in_reply_to_id: Some(synced_id),
+                ..new_reply
+            });
+            toots_order.push(index);
+            bsky_root_indices.insert(post.post.uri.clone(), updates.toots.len() - 1);
+        }
+        // Otherwise the parent hasn't been synced and isn't part of this
+        // run either, nothing to thread this reply onto yet.
     }
 
-    'toots: for toot in mastodon_statuses {
-        // Skip replies, they are handled in determine_thread_replies().
-        if let Some(_id) = &toot.in_reply_to_id {
+    for (index, toot) in mastodon_statuses.iter().enumerate().rev() {
+        let Some(parent_id) = &toot.in_reply_to_id else {
+            continue;
+        };
+        // Only thread replies that are entirely self-authored.
+        if toot.in_reply_to_account_id.as_ref() != Some(&toot.account.id) {
             continue;
         }
 
-        if toot.reblog.is_some() && !options.sync_reblogs {
-            // Skip reblogs when sync_reblogs is disabled
+        if options.sync_only_public && !toot_is_public(toot) {
             continue;
         }
-        let fulltext = mastodon_toot_get_text(toot);
-        // If this is a reblog/boost then take the URL to the original toot.
-        let post = match &toot.reblog {
-            None => bsky_post_shorten(&fulltext, &toot.url),
-            Some(reblog) => bsky_post_shorten(&fulltext, &reblog.url),
-        };
-        // Skip direct toots to other Mastodon users, even if they are public.
-        if post.starts_with('@') {
+
+        // Skip if this reply was already synced to Bluesky in a previous run.
+        if bsky_statuses
+            .iter()
+            .any(|post| post.reply.is_some() && toot_and_post_are_equal(toot, post, options))
+        {
             continue;
         }
 
-        for bsky_post in bsky_statuses {
-            // If the toot already exists we can stop here and know that we are
-            // synced.
-            if toot_and_post_are_equal(toot, bsky_post) {
-                break 'toots;
-            }
+        let fulltext = mastodon_toot_get_text(toot);
+        let post_text = match &toot.reblog {
+            None => bsky_post_shorten(&fulltext, &toot.url, options.bsky_max_chars),
+            Some(reblog) => bsky_post_shorten(&fulltext, &reblog.url, options.bsky_max_chars),
+        };
+        if post_text.starts_with('@') {
+            continue;
         }
-
-        // The toot is not on Bluesky yet, check if we should post it.
-        // Check if hashtag filtering is enabled and if the post matches.
         if let Some(sync_hashtag) = &options.sync_hashtag_mastodon {
             if !sync_hashtag.is_empty() && !fulltext.contains(sync_hashtag) {
-                // Skip if a sync hashtag is set and the string doesn't match.
                 continue;
             }
         }
+        if matches_any(&options.content_filters, &fulltext)
+            || matches_any(&options.keyword_blocklist, &fulltext)
+        {
+            continue;
+        }
 
-        updates.bsky_posts.push(NewStatus {
-            text: post,
-            attachments: toot_get_attachments(toot),
+        let new_reply = NewStatus {
+            text: post_text,
+            attachments: toot_get_attachments(toot, options),
             replies: Vec::new(),
             in_reply_to_id: None,
-        });
-    }
-
-    //determine_thread_replies(mastodon_statuses, bsky_statuses, options, &mut updates);
-
-    // Older posts should come first to preserve the ordering of posts to
-    // synchronize.
-    updates.reverse_order();
-    updates
-}
+        };
 
-/*fn bsky_post_is_reply(post: &Object<FeedViewPostData>) -> bool {
-    if let Some(_reskeet) = &post.post.viewer {
-        if let Some(_repost) = _reskeet.repost {
-            // Skip retweets when sync_retweets is disabled
-            continue;
+        if let Some(&root_index) = toot_root_indices.get(parent_id) {
+            updates.bsky_posts[root_index].replies.push(new_reply);
+            toot_root_indices.insert(toot.id.clone(), root_index);
+        } else if let Some(synced_uri) = mastodon_statuses
+            .iter()
+            .find(|candidate| &candidate.id == parent_id)
+            .and_then(|parent_toot| {
+                bsky_statuses
+                    .iter()
+                    .find(|post| toot_and_post_are_equal(parent_toot, post, options))
+            })
+            .map(|post| post.post.uri.clone())
+        {
+            updates.bsky_posts.push(NewStatus {
+                in_reply_to_id: Some(synced_uri),
+                ..new_reply
+            });
+            bsky_posts_order.push(index);
+            toot_root_indices.insert(toot.id.clone(), updates.bsky_posts.len() - 1);
         }
     }
-}*/
+}
+
+// Finds the Mastodon toot id that a given Bluesky post (usually a reply
+// parent) was already synced to in a previous run, by matching on text. Also
+// checks the shortened form of each toot, the same way toot_and_post_are_equal
+// does, so a root that needed shortening to fit bsky_max_chars still matches.
+fn find_synced_toot_id(
+    mastodon_statuses: &[Status],
+    parent: &Object<PostViewData>,
+    options: &SyncOptions,
+) -> Option<String> {
+    let parent_record =
+        bsky_sdk::api::app::bsky::feed::post::RecordData::try_from_unknown(parent.record.clone())
+            .ok()?;
+    let parent_text = unify_post_content(bsky_record_get_text(parent_record).ok()?);
+    mastodon_statuses
+        .iter()
+        .find(|toot| {
+            let toot_text = unify_post_content(mastodon_toot_get_text(toot));
+            if toot_text == parent_text {
+                return true;
+            }
+            let shortened_toot = unify_post_content(match &toot.reblog {
+                None => bsky_post_shorten(&toot_text, &toot.url, options.bsky_max_chars),
+                Some(reblog) => bsky_post_shorten(&toot_text, &reblog.url, options.bsky_max_chars),
+            });
+            shortened_toot == parent_text
+        })
+        .map(|toot| toot.id.clone())
+}
 
 // Returns true if a Mastodon toot and a Bluesky post are considered equal.
-pub fn toot_and_post_are_equal(toot: &Status, bsky_post: &Object<FeedViewPostData>) -> bool {
+pub fn toot_and_post_are_equal(
+    toot: &Status,
+    bsky_post: &Object<FeedViewPostData>,
+    options: &SyncOptions,
+) -> bool {
     // Make sure the structure is the same: both must be replies or both must
     // not be replies.
     if (toot.in_reply_to_id.is_some() && bsky_post.reply.is_none())
@@ -195,17 +583,21 @@ pub fn toot_and_post_are_equal(toot: &Status, bsky_post: &Object<FeedViewPostDat
 
     // Strip markup from Mastodon toot and unify message for comparison.
     let toot_text = unify_post_content(mastodon_toot_get_text(toot));
-    // Populate URLs in the post text.
-    let bsky_text = unify_post_content(bsky_post_unshorten_decode(bsky_post));
+    // Populate URLs in the post text. A malformed post record can't be
+    // compared, so treat it as not matching rather than panicking.
+    let bsky_text = match bsky_post_unshorten_decode(bsky_post, options) {
+        Ok(text) => unify_post_content(text),
+        Err(_) => return false,
+    };
 
     if toot_text == bsky_text {
         return true;
     }
-    // Mastodon allows up to 500 characters, so we might need to shorten the
-    // toot. If this is a reblog/boost then take the URL to the original toot.
+    // The toot might need shortening to fit the configured Bluesky limit. If
+    // this is a reblog/boost then take the URL to the original toot.
     let shortened_toot = unify_post_content(match &toot.reblog {
-        None => bsky_post_shorten(&toot_text, &toot.url),
-        Some(reblog) => bsky_post_shorten(&toot_text, &reblog.url),
+        None => bsky_post_shorten(&toot_text, &toot.url, options.bsky_max_chars),
+        Some(reblog) => bsky_post_shorten(&toot_text, &reblog.url, options.bsky_max_chars),
     });
 
     if shortened_toot == bsky_text {
@@ -215,6 +607,17 @@ pub fn toot_and_post_are_equal(toot: &Status, bsky_post: &Object<FeedViewPostDat
     false
 }
 
+// Returns true if any of the given patterns match the text. Used for
+// content_filters and keyword_blocklist in SyncOptions.
+fn matches_any(patterns: &[Regex], text: &str) -> bool {
+    patterns.iter().any(|pattern| pattern.is_match(text))
+}
+
+// Returns true if a toot is fully public, i.e. not unlisted/private/direct.
+fn toot_is_public(toot: &Status) -> bool {
+    matches!(toot.visibility, StatusVisibility::Public)
+}
+
 // Unifies bluesky text or toot text to a common format.
 fn unify_post_content(content: String) -> String {
     let mut result = content.to_lowercase();
@@ -230,12 +633,18 @@ fn unify_post_content(content: String) -> String {
 
 // Extend URLs and HTML entity decode &amp;.
 // Directly include quoted posts in the text.
-pub fn bsky_post_unshorten_decode(bsky_post: &Object<FeedViewPostData>) -> String {
+//
+// Returns an error instead of panicking on a malformed post record, since
+// streaming sync feeds this continuously from live, attacker-influenceable
+// data where a single bad record must not bring down the whole process.
+pub fn bsky_post_unshorten_decode(
+    bsky_post: &Object<FeedViewPostData>,
+    options: &SyncOptions,
+) -> Result<String> {
     let record = bsky_sdk::api::app::bsky::feed::post::RecordData::try_from_unknown(
         bsky_post.post.record.clone(),
-    )
-    .expect("Failed to parse Bluesky post record");
-    let mut text = bsky_record_get_text(record);
+    )?;
+    let mut text = bsky_record_get_text(record)?;
 
     // Add prefix for reposts.
     if let Some(viewer) = &bsky_post.post.viewer {
@@ -250,9 +659,8 @@ pub fn bsky_post_unshorten_decode(bsky_post: &Object<FeedViewPostData>) -> Strin
                 let quote_record =
                     bsky_sdk::api::app::bsky::feed::post::RecordData::try_from_unknown(
                         quote.value.clone(),
-                    )
-                    .expect("Failed to parse Bluesky quote post record");
-                let quote_text = bsky_record_get_text(quote_record);
+                    )?;
+                let quote_text = bsky_record_get_text(quote_record)?;
                 text = format!(
                     "{text}\n\n💬 {}: {quote_text}",
                     quote.author.handle.as_str()
@@ -260,39 +668,61 @@ pub fn bsky_post_unshorten_decode(bsky_post: &Object<FeedViewPostData>) -> Strin
             }
         }
     }
-    toot_shorten(&text, &bsky_post.post)
+    Ok(toot_shorten(&text, &bsky_post.post, options.mastodon_max_chars))
 }
 
 // Get the full text of a bluesky post.
-fn bsky_record_get_text(bsky_record: bsky_sdk::api::app::bsky::feed::post::RecordData) -> String {
+fn bsky_record_get_text(
+    bsky_record: bsky_sdk::api::app::bsky::feed::post::RecordData,
+) -> Result<String> {
     let mut text = bsky_record.text.clone();
     // Convert links in facets to URIs in the text.
     if let Some(facets) = &bsky_record.facets {
         for facet in facets {
             if let Union::Refs(MainFeaturesItem::Link(link)) = &facet.features[0] {
-                let mut bytes = bsky_record.text.as_bytes().to_vec();
+                let bytes = bsky_record.text.as_bytes();
+                // Facet byte ranges come straight off the firehose and aren't
+                // guaranteed to be in range or well-formed (start <= end,
+                // landing on char boundaries), so a malformed record must not
+                // be able to panic Vec::splice and take down the process.
+                if facet.index.byte_start > facet.index.byte_end
+                    || facet.index.byte_end > bytes.len()
+                    || !bsky_record.text.is_char_boundary(facet.index.byte_start)
+                    || !bsky_record.text.is_char_boundary(facet.index.byte_end)
+                {
+                    return Err(anyhow::anyhow!(
+                        "bluesky post has an out-of-range or invalid facet byte range: {}..{}",
+                        facet.index.byte_start,
+                        facet.index.byte_end
+                    ));
+                }
+                let mut bytes = bytes.to_vec();
                 bytes.splice(
                     facet.index.byte_start..facet.index.byte_end,
                     link.uri.as_bytes().iter().cloned(),
                 );
-                text = String::from_utf8(bytes)
-                    .expect("Invalid UTF-8 in Bluesky post after replacing link");
+                text = String::from_utf8(bytes)?;
             }
         }
     }
-    text
+    Ok(text)
 }
 
-pub fn bsky_post_shorten(text: &str, toot_url: &Option<String>) -> String {
+pub fn bsky_post_shorten(text: &str, toot_url: &Option<String>, max_chars: usize) -> String {
     let mut char_count = text.graphemes(true).count();
     let re = Regex::new(r"[^\s]+$").unwrap();
     let mut shortened = text.trim().to_string();
     let mut with_link = shortened.clone();
 
-    // Bluesky has a limit of 300 characters.
-    while char_count > 300 {
+    while char_count > max_chars {
         // Remove the last word.
-        shortened = re.replace_all(&shortened, "").trim().to_string();
+        let new_shortened = re.replace_all(&shortened, "").trim().to_string();
+        if new_shortened == shortened {
+            // No more words to remove, e.g. the link alone already exceeds
+            // max_chars. Bail out instead of looping forever.
+            break;
+        }
+        shortened = new_shortened;
         if let Some(ref toot_url) = *toot_url {
             // Add a link to the toot that has the full text.
             with_link = shortened.clone() + "… " + toot_url;
@@ -305,12 +735,12 @@ pub fn bsky_post_shorten(text: &str, toot_url: &Option<String>) -> String {
     with_link
 }
 
-// Mastodon has a 500 character post limit. With embedded quote posts and long
-// links the content could get too long, shorten it to 500 characters.
-fn toot_shorten(text: &str, bsky_post: &Object<PostViewData>) -> String {
+// Mastodon has a post character limit, which can be raised on custom
+// instances. With embedded quote posts and long links the content could get
+// too long, shorten it to the configured limit.
+fn toot_shorten(text: &str, bsky_post: &Object<PostViewData>, max_chars: usize) -> String {
     let mut char_count = text.graphemes(true).count();
-    // Hard-coding a limit of 500 here for now, could be configurable.
-    if char_count <= 500 {
+    if char_count <= max_chars {
         return text.to_string();
     }
     let last_word_regex = Regex::new(r"[^\s]+$").unwrap();
@@ -326,12 +756,18 @@ fn toot_shorten(text: &str, bsky_post: &Object<PostViewData>) -> String {
         .unwrap();
     let link = format!("https://bsky.app/profile/{username}/post/{post_id}");
 
-    while char_count > 500 {
+    while char_count > max_chars {
         // Remove the last word.
-        shortened = last_word_regex
+        let new_shortened = last_word_regex
             .replace_all(&shortened, "")
             .trim()
             .to_string();
+        if new_shortened == shortened {
+            // No more words to remove, e.g. the link alone already exceeds
+            // max_chars. Bail out instead of looping forever.
+            break;
+        }
+        shortened = new_shortened;
         // Add a link to the full length post on Bluesky.
         with_link = format!("{shortened}… {link}");
         char_count = with_link.graphemes(true).count();
@@ -419,7 +855,10 @@ pub fn read_post_cache(cache_file: &str) -> HashSet<String> {
 }
 
 // Returns a list of direct links to attachments for download.
-pub fn bsky_get_attachments(bsky_post: &Object<FeedViewPostData>) -> Vec<NewMedia> {
+pub fn bsky_get_attachments(
+    bsky_post: &Object<FeedViewPostData>,
+    options: &SyncOptions,
+) -> Vec<NewMedia> {
     let mut links = Vec::new();
 
     if let Some(embed) = &bsky_post.post.embed {
@@ -429,6 +868,7 @@ pub fn bsky_get_attachments(bsky_post: &Object<FeedViewPostData>) -> Vec<NewMedi
                     let images = &image_box.images;
                     for image in images {
                         links.push(NewMedia {
+                            cache_key: options.media_cache_url_index.get(&image.fullsize).cloned(),
                             attachment_url: image.fullsize.clone(),
                             alt_text: Some(image.alt.clone()),
                         });
@@ -443,7 +883,7 @@ pub fn bsky_get_attachments(bsky_post: &Object<FeedViewPostData>) -> Vec<NewMedi
 }
 
 // Returns a list of direct links to attachments for download.
-pub fn toot_get_attachments(toot: &Status) -> Vec<NewMedia> {
+pub fn toot_get_attachments(toot: &Status, options: &SyncOptions) -> Vec<NewMedia> {
     let mut links = Vec::new();
     let mut attachments = &toot.media_attachments;
     // If there are no attachments check if this is a boost and if there might
@@ -455,6 +895,7 @@ pub fn toot_get_attachments(toot: &Status) -> Vec<NewMedia> {
     }
     for attachment in attachments {
         links.push(NewMedia {
+            cache_key: options.media_cache_url_index.get(&attachment.url).cloned(),
             attachment_url: attachment.url.clone(),
             // Bluesky only allows a max length of 1,000 characters for alt
             // text, so we need to cut it off here.
@@ -488,8 +929,36 @@ pub mod tests {
     use bsky_sdk::api::types::Object;
     use std::fs;
 
+    use megalodon::entities::Status;
+    use regex::Regex;
+
+    use crate::sync::{bsky_post_shorten, sort_by_order, NewStatus};
     use crate::{determine_posts, sync::toot_shorten, SyncOptions};
 
+    // Test that a post matching a content filter is dropped entirely.
+    #[test]
+    fn bsky_content_filter_drops_post() {
+        let post = read_bsky_post_from_json("tests/bsky_quote_post.json");
+        let options = SyncOptions {
+            content_filters: vec![Regex::new("(?i)testing quote posts").unwrap()],
+            ..SyncOptions::default()
+        };
+        let posts = determine_posts(&Vec::new(), &vec![post], &options);
+        assert!(posts.toots.is_empty());
+    }
+
+    // Test that a post matching a blocklisted keyword is dropped entirely.
+    #[test]
+    fn bsky_keyword_blocklist_drops_post() {
+        let post = read_bsky_post_from_json("tests/bsky_quote_post.json");
+        let options = SyncOptions {
+            keyword_blocklist: vec![Regex::new(r"(?i)\btesting\b").unwrap()],
+            ..SyncOptions::default()
+        };
+        let posts = determine_posts(&Vec::new(), &vec![post], &options);
+        assert!(posts.toots.is_empty());
+    }
+
     // Test that embedded quote posts are included correctly.
     #[test]
     fn bsky_quote_post() {
@@ -516,7 +985,166 @@ https://github.com/klausi/mastodon-bluesky-sync/releases/tag/v0.2.0"
             "{}a… https://bsky.app/profile/klau.si/post/3lb3f2ko4rc23",
             "a ".repeat(223)
         );
-        assert_eq!(expected, toot_shorten(&text, &post.post));
+        assert_eq!(expected, toot_shorten(&text, &post.post, 500));
+    }
+
+    // Test that bsky_post_shorten honors a configured max_chars instead of
+    // the default Bluesky limit of 300.
+    #[test]
+    fn bsky_post_shorten_respects_configured_max_chars() {
+        let text = "a ".repeat(20);
+        assert_eq!(text.trim(), bsky_post_shorten(&text, &None, 300));
+        assert!(bsky_post_shorten(&text, &None, 10).len() <= 10);
+    }
+
+    // Test that shortening terminates instead of looping forever when the
+    // link to append is already longer than max_chars.
+    #[test]
+    fn bsky_post_shorten_terminates_when_link_exceeds_max_chars() {
+        let text = "a ".repeat(20);
+        let url = Some("https://example.com/toot/1".to_string());
+        let shortened = bsky_post_shorten(&text, &url, 5);
+        assert!(shortened.ends_with(&url.unwrap()));
+    }
+
+    // Test that a non-public toot is skipped when sync_only_public is on, but
+    // still synced once it's turned back off (the default).
+    #[test]
+    fn sync_only_public_gates_non_public_toots() {
+        let toot = read_toot_from_json("tests/mastodon_private_toot.json");
+
+        let options = SyncOptions {
+            sync_only_public: true,
+            ..SyncOptions::default()
+        };
+        let posts = determine_posts(&vec![toot.clone()], &Vec::new(), &options);
+        assert!(posts.bsky_posts.is_empty());
+
+        let posts = determine_posts(&vec![toot], &Vec::new(), &SyncOptions::default());
+        assert_eq!(posts.bsky_posts.len(), 1);
+    }
+
+    // Test that a self-reply on Mastodon nests into the root toot's
+    // cross-posted replies instead of becoming its own top-level post.
+    #[test]
+    fn mastodon_self_reply_nests_under_root() {
+        let root = read_toot_from_json("tests/mastodon_reply_root.json");
+        let reply = read_toot_from_json("tests/mastodon_reply_self.json");
+
+        let posts = determine_posts(&vec![reply, root], &Vec::new(), &SyncOptions::default());
+
+        assert_eq!(posts.bsky_posts.len(), 1);
+        assert_eq!(posts.bsky_posts[0].replies.len(), 1);
+        assert_eq!(
+            posts.bsky_posts[0].replies[0].text,
+            "Following up on my last toot"
+        );
+    }
+
+    // Test that a Mastodon reply addressed to a different account is dropped
+    // instead of being threaded onto an unrelated root.
+    #[test]
+    fn mastodon_reply_to_other_account_is_dropped() {
+        let root = read_toot_from_json("tests/mastodon_reply_root.json");
+        let reply = read_toot_from_json("tests/mastodon_reply_other_account.json");
+
+        let posts = determine_posts(&vec![reply, root], &Vec::new(), &SyncOptions::default());
+
+        assert_eq!(posts.bsky_posts.len(), 1);
+        assert!(posts.bsky_posts[0].replies.is_empty());
+    }
+
+    // Test that a self-reply on Bluesky nests into the root post's
+    // cross-posted replies instead of becoming its own top-level toot.
+    #[test]
+    fn bsky_self_reply_nests_under_root() {
+        let root = read_bsky_post_from_json("tests/bsky_reply_root.json");
+        let reply = read_bsky_post_from_json("tests/bsky_reply_self.json");
+
+        let posts = determine_posts(&Vec::new(), &vec![reply, root], &SyncOptions::default());
+
+        assert_eq!(posts.toots.len(), 1);
+        assert_eq!(posts.toots[0].replies.len(), 1);
+        assert_eq!(
+            posts.toots[0].replies[0].text,
+            "Following up on my last post"
+        );
+    }
+
+    // Test that a Bluesky reply addressed to a different account is dropped
+    // instead of being threaded onto an unrelated root.
+    #[test]
+    fn bsky_reply_to_other_author_is_dropped() {
+        let root = read_bsky_post_from_json("tests/bsky_reply_root.json");
+        let reply = read_bsky_post_from_json("tests/bsky_reply_other_author.json");
+
+        let posts = determine_posts(&Vec::new(), &vec![reply, root], &SyncOptions::default());
+
+        assert_eq!(posts.toots.len(), 1);
+        assert!(posts.toots[0].replies.is_empty());
+    }
+
+    // Test that a Bluesky reply whose parent was already cross-posted to
+    // Mastodon in an earlier run (so the parent isn't part of this run's
+    // bsky_statuses) still resolves via find_synced_toot_id() and is posted
+    // standalone with in_reply_to_id set, instead of being dropped or
+    // recreating the root.
+    #[test]
+    fn bsky_reply_resolves_parent_synced_in_earlier_run() {
+        let reply = read_bsky_post_from_json("tests/bsky_reply_self.json");
+        // Stands in for the root bsky post already synced in a previous run:
+        // same text as the already-synced Mastodon toot, but not a reply.
+        let already_synced_root = read_bsky_post_from_json("tests/bsky_reply_root.json");
+        let root_toot = read_toot_from_json("tests/mastodon_reply_root.json");
+
+        let posts = determine_posts(
+            &vec![root_toot.clone()],
+            &vec![reply, already_synced_root],
+            &SyncOptions::default(),
+        );
+
+        assert_eq!(posts.bsky_posts.len(), 0);
+        assert_eq!(posts.toots.len(), 1);
+        assert_eq!(posts.toots[0].text, "Following up on my last post");
+        assert_eq!(posts.toots[0].in_reply_to_id, Some(root_toot.id));
+        assert!(posts.toots[0].replies.is_empty());
+    }
+
+    // Test that sort_by_order() restores the newest-first order of the
+    // source statuses, even when entries were appended out of order (as
+    // determine_thread_replies() does for continuation posts).
+    #[test]
+    fn sort_by_order_restores_source_ordering() {
+        let mut items = vec![
+            new_status_with_text("root from this run"),
+            new_status_with_text("continuation of an older thread"),
+        ];
+        // The continuation post (source index 5, i.e. older) was appended
+        // after the root (source index 0, i.e. newer).
+        let order = vec![0, 5];
+        sort_by_order(&mut items, &order);
+        assert_eq!(items[0].text, "root from this run");
+        assert_eq!(items[1].text, "continuation of an older thread");
+
+        // Now check the actual bug scenario: the continuation post's source
+        // index is *older* than a root discovered later in the same run.
+        let mut items = vec![
+            new_status_with_text("continuation of an older thread"),
+            new_status_with_text("brand-new root"),
+        ];
+        let order = vec![5, 1];
+        sort_by_order(&mut items, &order);
+        assert_eq!(items[0].text, "brand-new root");
+        assert_eq!(items[1].text, "continuation of an older thread");
+    }
+
+    fn new_status_with_text(text: &str) -> NewStatus {
+        NewStatus {
+            text: text.to_string(),
+            attachments: Vec::new(),
+            replies: Vec::new(),
+            in_reply_to_id: None,
+        }
     }
 
     // Read static bluesky post from test file.
@@ -524,4 +1152,10 @@ https://github.com/klausi/mastodon-bluesky-sync/releases/tag/v0.2.0"
         let json = fs::read_to_string(file_name).unwrap();
         serde_json::from_str(&json).unwrap()
     }
+
+    // Read static Mastodon toot from test file.
+    fn read_toot_from_json(file_name: &str) -> Status {
+        let json = fs::read_to_string(file_name).unwrap();
+        serde_json::from_str(&json).unwrap()
+    }
 }